@@ -1,7 +1,5 @@
 use std::cell::Cell;
-use std::mem::swap;
 use std::error::Error;
-use std::collections::VecDeque;
 
 fn main() {
     println!("Hello, world!");
@@ -11,8 +9,13 @@ fn main() {
 #[derive(Clone, Debug)]
 enum Type {
     Literal(u16),
-    LiteralClass(Vec<u16>),
+    LiteralClass(Vec<u16>, bool),
     Begin,
+    End,
+    // Zero-width, unconditional: unlike `Begin`/`End` it holds no matter
+    // where `ptr` is. Used as the bypass/rejoin point a quantifier needs to
+    // make a unit skippable without threading state through its caller.
+    Epsilon,
     Match,
 }
 
@@ -69,273 +72,718 @@ impl State {
 struct Regex {
     states: Vec<State>,
     ptr: usize,
-    anchors: VecDeque<usize>,
-    group_anchors: Vec<usize>,
-    starts: Vec<Vec<usize>>,
-    ends: Vec<Vec<usize>>,
-    next_states: Vec<usize>,
+    group_count: usize,
+    // (state id, group number) pairs recording which capture groups a state
+    // opens/closes. A state can open or close more than one group when two
+    // `(` (or `)`) land on the same position, e.g. `((ab))`.
+    group_opens: Vec<(usize, usize)>,
+    group_closes: Vec<(usize, usize)>,
 }
 
+// The parsed shape of a pattern, built by `Regex::parse_alt` before any
+// `State`s exist. Keeping this as a separate stage from compilation means
+// precedence (alternation binds loosest, then concatenation, then
+// quantifiers) and nesting are resolved once, up front, instead of being
+// tangled up with NFA construction the way the old single-pass scan was.
+#[derive(Clone, Debug)]
+enum Ast {
+    Char(char),
+    AnyChar,
+    Class(Vec<u16>, bool),
+    Begin,
+    End,
+    Concat(Vec<Ast>),
+    Alt(Vec<Ast>),
+    Star(Box<Ast>),
+    Plus(Box<Ast>),
+    Opt(Box<Ast>),
+    Repeat(Box<Ast>, usize, Option<usize>),
+    Group(Box<Ast>, usize),
+}
+
+// A fragment of the NFA under construction: `entries` are the state ids an
+// earlier fragment should wire its own exits to in order to enter this one,
+// and `exits` are the state ids whose transitions still need to be wired to
+// whatever comes next. A state can appear in both lists at once (e.g. a
+// unit made optional is its own skip path), which is why these are plain
+// `Vec<usize>` rather than a single id apiece.
+type Fragment = (Vec<usize>, Vec<usize>);
+
+// A half-open `[start, end)` match span, in char-index units (not bytes).
+type Span = (usize, usize);
+
+// One entry per capture group, index 0 being the whole match; `None` when
+// that group took no part in the match (e.g. the unmatched side of a `|`).
+type Captures = Vec<Option<Span>>;
+
 impl Regex {
 
-    fn update_previous_nodes(&mut self, ptr: usize) {
-        for anchor in &self.anchors {
-            let anchor_state = &mut self.states[*anchor];
-            anchor_state.push_to_transitions(ptr);
+    // Parses `a|b|c` alternation, the loosest-binding operator: each `|`
+    // starts a fresh concatenation, and an empty branch on either side is a
+    // syntax error rather than an implicit empty match.
+    fn parse_alt(chars: &mut std::iter::Peekable<std::str::Chars>, group_count: &mut usize) -> Result<Ast, Box<dyn Error>> {
+        let mut branches = vec![Self::parse_concat(chars, group_count)?];
+        while chars.peek() == Some(&'|') {
+            chars.next();
+            branches.push(Self::parse_concat(chars, group_count)?);
+        }
+        if branches.len() == 1 {
+            Ok(branches.pop().unwrap())
+        } else {
+            Ok(Ast::Alt(branches))
         }
     }
 
-    fn handle_literal(&mut self, mut state: State, chars: &mut std::iter::Peekable<std::str::Chars>) -> bool {
-        self.ptr += 1;
-        let mut continue_loop = true;
+    // Parses a run of quantified atoms until `|`, `)`, or end of input. A
+    // branch with no atoms at all (`||`, a bare `|`, a dangling `(|`) is
+    // rejected here rather than silently compiling to an empty match.
+    fn parse_concat(chars: &mut std::iter::Peekable<std::str::Chars>, group_count: &mut usize) -> Result<Ast, Box<dyn Error>> {
+        let mut units = vec![];
+        while !matches!(chars.peek(), None | Some('|') | Some(')')) {
+            units.push(Self::parse_quantified(chars, group_count)?);
+        }
+        if units.is_empty() {
+            return Err("Invalid regex, compilation failed. Invalid regex pattern".into())
+        }
+        if units.len() == 1 {
+            Ok(units.pop().unwrap())
+        } else {
+            Ok(Ast::Concat(units))
+        }
+    }
+
+    // Parses a single atom followed by at most one trailing `*`, `+`, `?`
+    // or `{min,max}`. A second `*`, `+` or `?` immediately after the first
+    // (e.g. `a**`) is left unconsumed here and rejected by the next call to
+    // `parse_atom`, which has no atom to attach it to. A `{` in that position
+    // is not read as a repetition count: `parse_atom` takes it as a literal.
+    fn parse_quantified(chars: &mut std::iter::Peekable<std::str::Chars>, group_count: &mut usize) -> Result<Ast, Box<dyn Error>> {
+        let atom = Self::parse_atom(chars, group_count)?;
+        if chars.peek() == Some(&'{') {
+            let (min, max) = Self::parse_repetition(chars)?;
+            return Ok(Ast::Repeat(Box::new(atom), min, max));
+        }
         match chars.peek() {
-            Some(&'*') => {
-                chars.next();
-                state.push_to_transitions(self.ptr);
-                self.update_previous_nodes(self.ptr);
+            Some(&'*') => { chars.next(); Ok(Ast::Star(Box::new(atom))) },
+            Some(&'+') => { chars.next(); Ok(Ast::Plus(Box::new(atom))) },
+            Some(&'?') => { chars.next(); Ok(Ast::Opt(Box::new(atom))) },
+            _ => Ok(atom),
+        }
+    }
+
+    // Parses a single indivisible unit: a literal, `.`, a `^`/`$` anchor, a
+    // `[...]` class, a `\` escape, or a parenthesised group. Group numbers
+    // are assigned here, in the order their opening `(` is encountered,
+    // matching standard capture-group numbering.
+    fn parse_atom(chars: &mut std::iter::Peekable<std::str::Chars>, group_count: &mut usize) -> Result<Ast, Box<dyn Error>> {
+        match chars.next() {
+            Some('.') => Ok(Ast::AnyChar),
+            Some('^') => Ok(Ast::Begin),
+            Some('$') => Ok(Ast::End),
+            Some('[') => {
+                let (members, negated) = Self::scan_class(chars)?;
+                Ok(Ast::Class(members, negated))
             },
-            Some(&'?') => {
-                chars.next();
-                self.update_previous_nodes(self.ptr);
+            Some('(') => {
+                *group_count += 1;
+                let idx = *group_count;
+                let inner = Self::parse_alt(chars, group_count)?;
+                match chars.next() {
+                    Some(')') => Ok(Ast::Group(Box::new(inner), idx)),
+                    _ => Err("Invalid regex, compilation failed. Unclosed group".into()),
+                }
             },
-            Some(&'+') => {
-                chars.next();
-                state.push_to_transitions(self.ptr);
-                self.update_previous_nodes(self.ptr);
-                self.anchors.clear();
+            Some('*') | Some('+') | Some('?') => {
+                Err("Invalid regex, compilation failed. Invalid regex pattern".into())
             },
-            Some(_) => {
-                self.update_previous_nodes(self.ptr);
-                self.anchors.clear();
+            Some('\\') => match chars.next() {
+                Some('d') => Ok(Ast::Class(Self::digit_members(), false)),
+                Some('D') => Ok(Ast::Class(Self::digit_members(), true)),
+                Some('w') => Ok(Ast::Class(Self::word_members(), false)),
+                Some('W') => Ok(Ast::Class(Self::word_members(), true)),
+                Some('s') => Ok(Ast::Class(Self::space_members(), false)),
+                Some('S') => Ok(Ast::Class(Self::space_members(), true)),
+                Some(c) => Ok(Ast::Char(c)),
+                None => Err("Invalid regex, compilation failed. Trailing backslash".into()),
             },
-            None => {
-                continue_loop = false;
-                self.update_previous_nodes(self.ptr);
-                self.anchors.clear();
-            }
+            Some(c) => Ok(Ast::Char(c)),
+            None => Err("Invalid regex, compilation failed. Invalid regex pattern".into()),
         }
-        self.anchors.push_back(self.ptr);
-        self.states.push(state);
-        continue_loop
     }
 
-    fn handle_close_group(&mut self, chars: &mut std::iter::Peekable<std::str::Chars>) -> bool {
-        let start_ptr = self.group_anchors.pop().unwrap();
-        let group_start_ptrs = self.starts.pop().unwrap();
-        let group_end_ptrs = self.ends.pop().unwrap();
-        let mut continue_loop = true;
-        match chars.peek() {
-            Some(&'*') => {
-                chars.next();
-                for group_start_ptr in group_start_ptrs {
-                    for group_end_ptr in &group_end_ptrs {
-                        self.states[*group_end_ptr].push_to_transitions(group_start_ptr);
+    fn digit_members() -> Vec<u16> {
+        ('0'..='9').map(|c| c as u16).collect()
+    }
+
+    fn word_members() -> Vec<u16> {
+        ('a'..='z')
+            .chain('A'..='Z')
+            .chain('0'..='9')
+            .chain(std::iter::once('_'))
+            .map(|c| c as u16)
+            .collect()
+    }
+
+    fn space_members() -> Vec<u16> {
+        [' ', '\t', '\n', '\r', '\x0b', '\x0c'].iter().map(|&c| c as u16).collect()
+    }
+
+    // Lowers a parsed `Ast` onto `self.states`, returning the fragment's
+    // entries and exits so the caller can wire it into its surroundings.
+    // This is the only place that constructs `State`s from a pattern; the
+    // matching engine below (`step`, `find_in`, `captures_in`) is unchanged
+    // by which front end produced the states it walks.
+    fn lower(&mut self, node: &Ast) -> Result<Fragment, Box<dyn Error>> {
+        match node {
+            Ast::Char(c) => Ok(self.lower_leaf(Type::Literal(*c as u16))),
+            Ast::AnyChar => Ok(self.lower_leaf(Type::Literal(256))),
+            Ast::Class(members, negated) => Ok(self.lower_leaf(Type::LiteralClass(members.clone(), *negated))),
+            Ast::Begin => Ok(self.lower_leaf(Type::Begin)),
+            Ast::End => Ok(self.lower_leaf(Type::End)),
+            Ast::Concat(children) => {
+                let mut children = children.iter();
+                let (entries, mut exits) = self.lower(children.next().unwrap())?;
+                for child in children {
+                    let (next_entries, next_exits) = self.lower(child)?;
+                    for &exit in &exits {
+                        for &entry in &next_entries {
+                            self.states[exit].push_to_transitions(entry);
+                        }
                     }
-                    self.states[start_ptr].push_to_transitions(group_start_ptr);
-                    self.update_previous_nodes(group_start_ptr);
+                    exits = next_exits;
                 }
-                for group_end_ptr in &group_end_ptrs {
-                    self.states[*group_end_ptr].push_to_transitions(self.ptr + 1);
+                Ok((entries, exits))
+            },
+            Ast::Alt(branches) => {
+                let mut entries = vec![];
+                let mut exits = vec![];
+                for branch in branches {
+                    let (branch_entries, branch_exits) = self.lower(branch)?;
+                    entries.extend(branch_entries);
+                    exits.extend(branch_exits);
                 }
-
-                self.states[start_ptr].push_to_transitions(self.ptr + 1);
+                Ok((entries, exits))
             },
-            Some(&'?') => {
-                chars.next();
-                for group_start_ptr in group_start_ptrs {
-                    for group_end_ptr in &group_end_ptrs {
-                        self.states[*group_end_ptr].push_to_transitions(group_start_ptr);
-                    }
-                    self.states[start_ptr].push_to_transitions(group_start_ptr);
-                    self.update_previous_nodes(group_start_ptr);
+            // `*`, `+` and `?` are each just a `{min,max}` shorthand, so all
+            // three flow through the same counted-repetition lowering.
+            Ast::Star(inner) => self.lower_repeat(inner, 0, None),
+            Ast::Plus(inner) => self.lower_repeat(inner, 1, None),
+            Ast::Opt(inner) => self.lower_repeat(inner, 0, Some(1)),
+            Ast::Repeat(inner, min, max) => self.lower_repeat(inner, *min, *max),
+            Ast::Group(inner, idx) => {
+                let (entries, exits) = self.lower(inner)?;
+                for &entry in &entries {
+                    self.group_opens.push((entry, *idx));
                 }
-                for group_end_ptr in &group_end_ptrs {
-                    self.states[*group_end_ptr].push_to_transitions(self.ptr + 1);
+                for &exit in &exits {
+                    self.group_closes.push((exit, *idx));
                 }
-                self.states[start_ptr].push_to_transitions(self.ptr + 1);
+                Ok((entries, exits))
             },
-            Some(&'+') => {
+        }
+    }
+
+    fn lower_leaf(&mut self, t: Type) -> Fragment {
+        self.ptr += 1;
+        let id = self.ptr;
+        self.states.push(State::new(id, t, vec![]));
+        (vec![id], vec![id])
+    }
+
+    fn wire(states: &mut [State], from: &[usize], to: &[usize]) {
+        for &from in from {
+            for &to in to {
+                states[from].push_to_transitions(to);
+            }
+        }
+    }
+
+    fn union(a: &[usize], b: &[usize]) -> Vec<usize> {
+        let mut out = a.to_vec();
+        for &id in b {
+            if !out.contains(&id) {
+                out.push(id);
+            }
+        }
+        out
+    }
+
+    // Expands `inner` into `min..=max` (or `min..` when `max` is `None`)
+    // copies via `clone_subautomaton`, chaining them together. A repeated
+    // group (`(ab){2,3}`) and a repeated literal/class (`a{2,3}`) both flow
+    // through here uniformly, unlike the old ad-hoc scan which needed two
+    // separate codepaths for the two cases.
+    fn lower_repeat(&mut self, inner: &Ast, min: usize, max: Option<usize>) -> Result<Fragment, Box<dyn Error>> {
+        let lo = self.ptr + 1;
+        let (entries0, exits0) = self.lower(inner)?;
+        let hi = self.ptr;
+
+        let total = match max {
+            Some(m) => m,
+            None => min.max(1),
+        };
+
+        if total == 0 {
+            // `{0}` (min is forced to 0 by parse_repetition whenever max is
+            // 0) matches only the empty string. `inner` is still lowered
+            // above so its syntax and group numbering land, but no copy of
+            // it is ever wired in, so it can never consume a character.
+            let split = self.lower_epsilon();
+            return Ok((vec![split], vec![split]));
+        }
+
+        let mut copy_entries = vec![entries0];
+        let mut copy_exits = vec![exits0];
+        for _ in 1..total {
+            let (e, x) = self.clone_subautomaton(lo, hi, &copy_entries[0], &copy_exits[0]);
+            // `clone_subautomaton` pushes new states straight onto
+            // `self.states` without moving `self.ptr`, so every later id
+            // allocation (the next clone, the following atom, `Match`) has
+            // to see the clone's ids as taken or it'll reuse them.
+            self.ptr = self.states.len() - 1;
+            copy_entries.push(e);
+            copy_exits.push(x);
+        }
+
+        for i in 1..total {
+            Self::wire(&mut self.states, &copy_exits[i - 1], &copy_entries[i]);
+        }
+
+        if max.is_none() {
+            let last = total - 1;
+            Self::wire(&mut self.states, &copy_exits[last], &copy_entries[last]);
+        }
+
+        let start_stop_idx = if min == 0 { 0 } else { min - 1 };
+        let mut exits = vec![];
+        for copy_exit in &copy_exits[start_stop_idx..total] {
+            exits = Self::union(&exits, copy_exit);
+        }
+
+        if min == 0 {
+            // Zero repetitions must be reachable without consuming a
+            // character, which a real (already-consuming) state can't do on
+            // its own. `split` is a zero-width bypass: entering it can go
+            // straight to whatever follows the whole construct, or into the
+            // first copy to attempt a repetition.
+            let split = self.lower_epsilon();
+            Self::wire(&mut self.states, &[split], &copy_entries[0]);
+            exits = Self::union(&exits, &[split]);
+            Ok((vec![split], exits))
+        } else {
+            Ok((copy_entries[0].clone(), exits))
+        }
+    }
+
+    fn lower_epsilon(&mut self) -> usize {
+        self.ptr += 1;
+        let id = self.ptr;
+        self.states.push(State::new(id, Type::Epsilon, vec![]));
+        id
+    }
+
+    // Parses a `{min}`, `{min,}` or `{min,max}` repetition count after the
+    // opening `{` has already been peeked (but not consumed). Returns an
+    // error if the braces are unterminated, empty, non-numeric, or if
+    // `max < min`.
+    fn parse_repetition(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<(usize, Option<usize>), Box<dyn Error>> {
+        chars.next();
+        let mut min_digits = String::new();
+        while let Some(&c) = chars.peek() {
+            if c.is_ascii_digit() {
+                min_digits.push(c);
                 chars.next();
-                for group_start_ptr in group_start_ptrs {
-                    for group_end_ptr in &group_end_ptrs {
-                        self.states[*group_end_ptr].push_to_transitions(group_start_ptr);
+            } else {
+                break;
+            }
+        }
+        if min_digits.is_empty() {
+            return Err("Invalid regex, compilation failed. Invalid repetition count".into())
+        }
+        let min: usize = min_digits.parse()?;
+
+        let max = match chars.peek() {
+            Some(&',') => {
+                chars.next();
+                let mut max_digits = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_ascii_digit() {
+                        max_digits.push(c);
+                        chars.next();
+                    } else {
+                        break;
                     }
-                    self.states[start_ptr].push_to_transitions(group_start_ptr);
-                    self.update_previous_nodes(group_start_ptr);
                 }
-                for group_end_ptr in &group_end_ptrs {
-                    self.states[*group_end_ptr].push_to_transitions(self.ptr + 1);
+                if max_digits.is_empty() {
+                    None
+                } else {
+                    Some(max_digits.parse::<usize>()?)
                 }
-
             },
-            Some(_) => {
-                for group_end_ptr in &group_end_ptrs {
-                    self.states[*group_end_ptr].push_to_transitions(self.ptr + 1);
-                }
-            },
-            None => {
-                continue_loop = false;
+            _ => Some(min),
+        };
+
+        match chars.next() {
+            Some('}') => {},
+            _ => return Err("Invalid regex, compilation failed. Unterminated repetition count".into())
+        }
+
+        if let Some(m) = max {
+            if m < min {
+                return Err("Invalid regex, compilation failed. Invalid repetition count".into())
             }
         }
-        continue_loop
+
+        Ok((min, max))
     }
 
-    fn update_ends(&mut self, ptr: usize) {
-        let ends = self.ends.pop().unwrap();
-        for end in ends {
-            self.states[end].push_to_transitions(ptr);
+    // Duplicates the state range `[lo, hi]`, remapping transitions that stay
+    // inside the range to the new copy while leaving transitions that point
+    // outside the range untouched, since those still target the original
+    // shared continuation. `entries`/`exits` are the caller's view of which
+    // states in the range are reachable from outside / lead back outside, and
+    // are returned remapped to the copy so the caller can wire the copy up.
+    fn clone_subautomaton(&mut self, lo: usize, hi: usize, entries: &[usize], exits: &[usize]) -> (Vec<usize>, Vec<usize>) {
+        let offset = self.states.len();
+        let remap = |id: usize| -> usize {
+            if id >= lo && id <= hi { id - lo + offset } else { id }
+        };
+        for old_id in lo..=hi {
+            let old_transitions = self.states[old_id].transitions.clone();
+            let new_transitions: Vec<usize> = old_transitions.iter().map(|t| remap(*t)).collect();
+            let new_type = self.states[old_id].t.clone();
+            let new_id = remap(old_id);
+            self.states.push(State::new(new_id, new_type, new_transitions));
         }
+        // A group nested inside the repeated unit (e.g. `(ab){2}`) opens and
+        // closes on states in `[lo, hi]`; the clone needs its own entries
+        // here too, or captures() only ever sees the first iteration's span.
+        let cloned_opens: Vec<(usize, usize)> = self.group_opens.iter()
+            .filter(|&&(id, _)| id >= lo && id <= hi)
+            .map(|&(id, group_idx)| (remap(id), group_idx))
+            .collect();
+        self.group_opens.extend(cloned_opens);
+        let cloned_closes: Vec<(usize, usize)> = self.group_closes.iter()
+            .filter(|&&(id, _)| id >= lo && id <= hi)
+            .map(|&(id, group_idx)| (remap(id), group_idx))
+            .collect();
+        self.group_closes.extend(cloned_closes);
+        let new_entries = entries.iter().map(|e| remap(*e)).collect();
+        let new_exits = exits.iter().map(|e| remap(*e)).collect();
+        (new_entries, new_exits)
     }
 
-    fn finish_regex(&mut self) -> Result<(), Box<dyn Error>> {
-        let new_ptr = self.ptr + 1;
-        let state = State::new(new_ptr, Type::Match, vec![]);
+    // Scans a `[...]` bracket expression after the opening `[` has already been
+    // consumed, expanding `x-y` ranges and honouring a leading `^` as negation.
+    // A `]` immediately after `[` or `[^` is treated as a literal member rather
+    // than the closing bracket, per POSIX bracket-expression semantics.
+    fn scan_class(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<(Vec<u16>, bool), Box<dyn Error>> {
+        let mut members: Vec<u16> = vec![];
+        let negated = if chars.peek() == Some(&'^') {
+            chars.next();
+            true
+        } else {
+            false
+        };
 
-        self.update_previous_nodes(new_ptr);
-        self.update_ends(new_ptr);
-        self.states.push(state);
-        self.ptr = 0;
-        Ok(())
+        let mut first = true;
+        loop {
+            let c = match chars.next() {
+                Some(c) => c,
+                None => return Err("Invalid regex, compilation failed. Unterminated character class".into())
+            };
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+
+            if chars.peek() == Some(&'-') {
+                let mut lookahead = chars.clone();
+                lookahead.next();
+                match lookahead.peek() {
+                    Some(&end) if end != ']' => {
+                        chars.next();
+                        let end = chars.next().unwrap();
+                        for cp in (c as u16)..=(end as u16) {
+                            if !members.contains(&cp) {
+                                members.push(cp);
+                            }
+                        }
+                        continue;
+                    },
+                    _ => {}
+                }
+            }
+
+            if !members.contains(&(c as u16)) {
+                members.push(c as u16);
+            }
+        }
+
+        Ok((members, negated))
     }
 
+    // Parses `pattern` into an `Ast` and lowers it onto a fresh `states`
+    // vector. State 0 is a sentinel whose own `Type` is never tested (only
+    // its transitions are ever walked, by `find_in`/`captures_in`); it just
+    // gives the matcher a single, fixed entry point to re-seed at every
+    // offset during an unanchored search.
     fn compile(pattern: &str) -> Result<Self, Box<dyn Error>> {
+        if pattern.is_empty() {
+            return Err("Invalid regex, compilation failed. Empty patterns are not allowed".into())
+        }
+
+        let mut chars = pattern.chars().peekable();
+        let mut group_count = 0usize;
+        let ast = Self::parse_alt(&mut chars, &mut group_count)?;
+        if chars.peek().is_some() {
+            return Err("Invalid regex, compilation failed. Invalid regex pattern".into())
+        }
+
         let mut regex = Self {
-            states: vec![State::new(0, Type::Begin, vec![1]) ],
+            states: vec![State::new(0, Type::Begin, vec![])],
             ptr: 0,
-            anchors:  VecDeque::from_iter(vec![0usize]),
-            group_anchors: vec![0],
-            starts: vec![vec![]],
-            ends: vec![vec![]],
-            next_states: vec![0]
+            group_count,
+            group_opens: vec![],
+            group_closes: vec![],
         };
 
-        if pattern.is_empty() {
-            return Err("Invalid regex, compilation failed. Empty patterns are not allowed".into())
+        let (entries, exits) = regex.lower(&ast)?;
+        for &entry in &entries {
+            regex.states[0].push_to_transitions(entry);
         }
 
-        let mut chars = pattern.chars().peekable();
-        eprintln!("Regex: {:?}", regex);
-        while let Some(c) = chars.next() {
-            eprintln!("Processing char: {}", c);
-            match c {
-                '.' => {
-                    // If the next character is a dot we create a state with Literal containing 256
-                    // to represent any character
-                    let state = State::new(regex.ptr + 1, Type::Literal(256), vec![]);
-                    if !regex.handle_literal(state, &mut chars) {
-                        break
+        let match_id = regex.ptr + 1;
+        regex.states.push(State::new(match_id, Type::Match, vec![]));
+        for &exit in &exits {
+            regex.states[exit].push_to_transitions(match_id);
+        }
+        regex.ptr = 0;
+
+        Ok(regex)
+    }
+
+    // Chases `^`/`$` anchors and `Epsilon` states past `state_id` without
+    // consuming a character, pushing every other state it reaches into
+    // `out`; an anchor whose condition fails ends that path. `ptr` is the
+    // number of haystack characters already consumed and `len` the total
+    // haystack length, so `Begin` holds at `ptr == 0` and `End` at `ptr == len`.
+    fn follow_epsilon(&self, state_id: usize, ptr: usize, len: usize, out: &mut Vec<usize>) {
+        match &self.states[state_id].t {
+            Type::Begin => {
+                if ptr == 0 {
+                    for t in self.states[state_id].transitions.clone() {
+                        self.follow_epsilon(t, ptr, len, out);
                     }
-                },
-                '|' => {
-                    // A regex pattern cant end with a pipe
-                    if chars.peek().is_none() {
-                        return Err("Invalid regex, compilation failed. Invalid regex pattern".into())
+                }
+            },
+            Type::End => {
+                if ptr == len {
+                    for t in self.states[state_id].transitions.clone() {
+                        self.follow_epsilon(t, ptr, len, out);
                     }
+                }
+            },
+            Type::Epsilon => {
+                for t in self.states[state_id].transitions.clone() {
+                    self.follow_epsilon(t, ptr, len, out);
+                }
+            },
+            _ => out.push(state_id),
+        }
+    }
 
-                    // Add the current state to the next states to ends and starts respectively
-                    for anchor in &regex.anchors {
-                        regex.ends.last_mut().unwrap().push(*anchor);
+    fn step(&self, transition: usize, c: char, ptr: usize, len: usize, new_states: &mut Vec<usize>) {
+        let mut candidates = vec![];
+        self.follow_epsilon(transition, ptr, len, &mut candidates);
+        for candidate in candidates {
+            match &self.states[candidate].t {
+                Type::Literal(ch) => {
+                    if *ch == 256 || c as u16 == *ch {
+                        new_states.push(candidate);
                     }
-                    regex.starts.last_mut().unwrap().push(regex.ptr + 1);
-
-                    // Push the  next state to the transitions of the start of the current group
-                    let start_ptr = *regex.group_anchors.last().unwrap();
-                    regex.states[start_ptr].push_to_transitions(regex.ptr + 1);
-
-                },
-                '(' => {
-                    regex.group_anchors.push(regex.ptr);
-                    regex.starts.push(vec![regex.ptr + 1]);
-                    regex.ends.push(vec![]);
                 },
-                ')' => {
-                    regex.ends.last_mut().unwrap().push(regex.ptr);
-                    eprintln!("ends: {:?}", regex.ends);
-                    if !regex.handle_close_group(&mut chars) {
-                        break
+                Type::LiteralClass(chars, negated) => {
+                    if chars.contains(&(c as u16)) != *negated {
+                        new_states.push(candidate);
                     }
                 },
-                '*' | '+' | '?' => {
-                    return Err("Invalid regex, compilation failed. Invalid regex pattern".into())
+                Type::Match => {
+                    new_states.push(candidate);
+                },
+                Type::Begin | Type::End | Type::Epsilon => {
+                    // follow_epsilon never yields a zero-width state itself
                 },
-                c => {
-                    let state = State::new(regex.ptr + 1, Type::Literal(c as u16), vec![]);
-                    if !regex.handle_literal(state, &mut chars) {
-                        break
-                    }
-                }
             }
-            eprintln!("Regex: {:?}", regex);
         }
-        regex.finish_regex()?;
-        eprintln!("\n\nRegex: {:?}\n\n", regex);
-        Ok(regex)
     }
 
-    fn get_next_state(&mut self) -> Option<usize> {
-        self.next_states.pop()
+    // Prefers the candidate with the smaller start; when starts tie, prefers
+    // the larger end. This is the leftmost-longest convention posix-regex
+    // follows for unanchored matches.
+    fn leftmost_longest(best: Option<Span>, candidate: Span) -> Option<Span> {
+        match best {
+            None => Some(candidate),
+            Some(b) if candidate.0 < b.0 || (candidate.0 == b.0 && candidate.1 > b.1) => Some(candidate),
+            Some(b) => Some(b),
+        }
     }
 
-    fn step(&self, transition: usize, c: char, new_states: &mut Vec<usize>) {
-        match &self.states[transition].t {
-            Type::Literal(ch) => {
-                if *ch == 256 || c as u16 == *ch {
-                    new_states.push(transition);
-                    println!("character: {} matched state: {:?}", c, &self.states[transition]);
-                }
-            },
-            Type::LiteralClass(chars) => {
-                if chars.contains(&(c as u16)) {
-                    new_states.push(transition);
-                    println!("character: {} matched state: {:?}", c, &self.states[transition]);
+    // Unanchored search: at every offset a fresh lineage is seeded by
+    // re-entering the unconditional sentinel state 0 (whose transitions
+    // compile wires to the pattern's entries), so matching can begin anywhere in
+    // `haystack`. Each live state carries the earliest offset whose lineage
+    // reached it, and every offset where `Match` is reachable (possibly
+    // through `^`/`$` anchors via `follow_epsilon`) is recorded as a
+    // `(start, end)` candidate. The leftmost-longest candidate across the
+    // whole scan is returned.
+    fn find_in(&self, mut haystack: HayStack) -> Option<Span> {
+        let len = haystack.s.len();
+        let mut actives: Vec<(usize, usize)> = vec![];
+        let mut best: Option<Span> = None;
+        let mut ptr = 0usize;
+
+        loop {
+            if !actives.iter().any(|(s, _)| *s == 0) {
+                actives.push((0, ptr));
+            }
+
+            for &(state, start) in &actives {
+                for transition in self.states[state].transitions.clone() {
+                    let mut candidates = vec![];
+                    self.follow_epsilon(transition, ptr, len, &mut candidates);
+                    if candidates.iter().any(|c| matches!(self.states[*c].t, Type::Match)) {
+                        best = Self::leftmost_longest(best, (start, ptr));
+                    }
                 }
-            },
-            Type::Match => {
-                new_states.push(transition);
-                println!( "{} matched state: {:?}", c, &self.states[transition]);
             }
-            Type::Begin => {
-                new_states.push(transition);
-                println!( "{} matched state: {:?}", c, &self.states[transition]);
+
+            let c = match haystack.get_char() {
+                Some(c) => c,
+                None => break,
+            };
+
+            let mut new_actives: Vec<(usize, usize)> = vec![];
+            for (state, start) in &actives {
+                for transition in self.states[*state].transitions.clone() {
+                    let mut new_states = vec![];
+                    self.step(transition, c, ptr, len, &mut new_states);
+                    for new_state in new_states {
+                        match new_actives.iter().position(|(s, _)| *s == new_state) {
+                            Some(i) => new_actives[i].1 = new_actives[i].1.min(*start),
+                            None => new_actives.push((new_state, *start)),
+                        }
+                    }
+                }
             }
+            actives = new_actives;
+            ptr += 1;
+        }
+
+        best
+    }
+
+    // Same leftmost-longest comparison as `leftmost_longest`, but carrying a
+    // capture vector alongside the span.
+    fn leftmost_longest_captures(
+        best: Option<(usize, usize, Captures)>,
+        candidate: (usize, usize, Captures),
+    ) -> Option<(usize, usize, Captures)> {
+        match &best {
+            None => Some(candidate),
+            Some((bs, be, _)) if candidate.0 < *bs || (candidate.0 == *bs && candidate.1 > *be) => Some(candidate),
+            Some(_) => best,
         }
     }
 
-    fn found_match(&self) -> bool {
-        for state in &self.next_states {
-            for transition in &self.states[*state].transitions {
-                if let Type::Match =  self.states[*transition].t {
-                   return true
+    // Same unanchored scan as `find_in`, but each lineage also carries a
+    // capture vector (index 0 reserved for the whole match, 1.. for `(`
+    // groups in the order they open). Entering one of `group_opens`'s states
+    // (re)starts that group's span at the current offset; entering one of
+    // `group_closes`'s states extends it to just past the current character.
+    // Repeating a group, as in `(ab)+`, naturally overwrites the previous
+    // iteration's span since the lineage simply revisits the same states.
+    fn captures_in(&self, mut haystack: HayStack) -> Option<Captures> {
+        let len = haystack.s.len();
+        let empty_caps: Captures = vec![None; self.group_count + 1];
+        let mut actives: Vec<(usize, usize, Captures)> = vec![];
+        let mut best: Option<(usize, usize, Captures)> = None;
+        let mut ptr = 0usize;
+
+        loop {
+            if !actives.iter().any(|(s, _, _)| *s == 0) {
+                actives.push((0, ptr, empty_caps.clone()));
+            }
+
+            for (state, start, caps) in &actives {
+                for transition in self.states[*state].transitions.clone() {
+                    let mut candidates = vec![];
+                    self.follow_epsilon(transition, ptr, len, &mut candidates);
+                    if candidates.iter().any(|c| matches!(self.states[*c].t, Type::Match)) {
+                        best = Self::leftmost_longest_captures(best, (*start, ptr, caps.clone()));
+                    }
                 }
             }
-        }
 
-        false
-    }
+            let c = match haystack.get_char() {
+                Some(c) => c,
+                None => break,
+            };
 
-    fn r#match(&mut self, mut haystack: HayStack) -> bool {
-        while let Some(c) = haystack.get_char() {
-            // println!("Processing char {}", c);
-            let mut new_states = vec![];
-            while let Some(ref current_state) = self.get_next_state() {
-                let state = &self.states[*current_state];
-                // println!("Processing state {:?}", &state);
-                for transition in &state.transitions {
-                    self.step(*transition, c, &mut new_states);
-                };
+            let mut new_actives: Vec<(usize, usize, Captures)> = vec![];
+            for (state, start, caps) in &actives {
+                for transition in self.states[*state].transitions.clone() {
+                    let mut new_states = vec![];
+                    self.step(transition, c, ptr, len, &mut new_states);
+                    for new_state in new_states {
+                        let mut new_caps = caps.clone();
+                        for &(open_state, group_idx) in &self.group_opens {
+                            if open_state == new_state {
+                                new_caps[group_idx] = Some((ptr, ptr + 1));
+                            }
+                        }
+                        for &(close_state, group_idx) in &self.group_closes {
+                            if close_state != new_state {
+                                continue;
+                            }
+                            if let Some((group_start, _)) = new_caps[group_idx] {
+                                new_caps[group_idx] = Some((group_start, ptr + 1));
+                            }
+                        }
+                        match new_actives.iter().position(|(s, _, _)| *s == new_state) {
+                            Some(i) if *start < new_actives[i].1 => new_actives[i] = (new_state, *start, new_caps),
+                            Some(_) => {},
+                            None => new_actives.push((new_state, *start, new_caps)),
+                        }
+                    }
+                }
             }
-            swap(&mut self.next_states, &mut new_states);
+            actives = new_actives;
+            ptr += 1;
         }
-        self.found_match()
+
+        best.map(|(start, end, mut caps)| {
+            caps[0] = Some((start, end));
+            caps
+        })
     }
 
 }
 
-pub fn is_match(s: String, p: String) -> Result<bool, Box<dyn Error>> {
-    println!("checking: {} against: {}", s, p);
-    let mut regex = Regex::compile(&p)?;
+pub fn find(s: String, p: String) -> Result<Option<Span>, Box<dyn Error>> {
+    let regex = Regex::compile(&p)?;
     let haystack = HayStack::new(&s);
-    Ok(regex.r#match(haystack))
+    Ok(regex.find_in(haystack))
+}
+
+pub fn captures(s: String, p: String) -> Result<Option<Captures>, Box<dyn Error>> {
+    let regex = Regex::compile(&p)?;
+    let haystack = HayStack::new(&s);
+    Ok(regex.captures_in(haystack))
+}
+
+pub fn is_match(s: String, p: String) -> Result<bool, Box<dyn Error>> {
+    Ok(find(s, p)?.is_some())
 }
 
 
@@ -484,6 +932,240 @@ mod tests {
         assert!(is_match("_".to_string(), "a+|".to_string()).is_err());
     }
 
+    #[test]
+    fn test_bracket_class_match() {
+        assert!(is_match("b".to_string(), "[abc]".to_string()).unwrap());
+        assert!(!is_match("d".to_string(), "[abc]".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_bracket_class_range() {
+        assert!(is_match("k".to_string(), "[a-z0-9]".to_string()).unwrap());
+        assert!(is_match("5".to_string(), "[a-z0-9]".to_string()).unwrap());
+        assert!(!is_match("!".to_string(), "[a-z0-9]".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_bracket_class_negated() {
+        assert!(is_match("x".to_string(), "[^aeiou]".to_string()).unwrap());
+        assert!(!is_match("a".to_string(), "[^aeiou]".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_bracket_class_leading_bracket_is_literal() {
+        assert!(is_match("]".to_string(), "[]a]".to_string()).unwrap());
+        assert!(is_match("a".to_string(), "[]a]".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_bracket_class_with_quantifier() {
+        assert!(is_match("cabbbd".to_string(), "c[ab]+d".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_begin_anchor_match() {
+        assert!(is_match("abc".to_string(), "^abc".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_end_anchor_match() {
+        assert!(is_match("abc".to_string(), "abc$".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_end_anchor_no_match_with_trailing_chars() {
+        assert!(!is_match("abcd".to_string(), "abc$".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_begin_and_end_anchor_match() {
+        assert!(is_match("abc".to_string(), "^abc$".to_string()).unwrap());
+        assert!(!is_match("xabc".to_string(), "^abc$".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_find_leftmost() {
+        assert_eq!(find("xxabcxx".to_string(), "abc".to_string()).unwrap(), Some((2, 5)));
+    }
+
+    #[test]
+    fn test_find_no_match() {
+        assert_eq!(find("xxxxx".to_string(), "abc".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_find_longest_at_leftmost_start() {
+        assert_eq!(find("aaab".to_string(), "a+".to_string()).unwrap(), Some((0, 3)));
+    }
+
+    #[test]
+    fn test_find_respects_explicit_anchors() {
+        assert_eq!(find("xabc".to_string(), "^abc".to_string()).unwrap(), None);
+        assert_eq!(find("abcx".to_string(), "abc$".to_string()).unwrap(), None);
+        assert_eq!(find("abc".to_string(), "^abc$".to_string()).unwrap(), Some((0, 3)));
+    }
+
+    #[test]
+    fn test_is_match_via_find_substring() {
+        assert!(is_match("xxabcxx".to_string(), "abc".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_captures_whole_match_is_group_zero() {
+        let caps = captures("xxabcxx".to_string(), "abc".to_string()).unwrap().unwrap();
+        assert_eq!(caps[0], Some((2, 5)));
+    }
+
+    #[test]
+    fn test_captures_single_group() {
+        let caps = captures("cabd".to_string(), "c(ab)d".to_string()).unwrap().unwrap();
+        assert_eq!(caps.len(), 2);
+        assert_eq!(caps[1], Some((1, 3)));
+    }
+
+    #[test]
+    fn test_captures_sibling_groups() {
+        let caps = captures("ab".to_string(), "(a)(b)".to_string()).unwrap().unwrap();
+        assert_eq!(caps[1], Some((0, 1)));
+        assert_eq!(caps[2], Some((1, 2)));
+    }
+
+    #[test]
+    fn test_captures_repeated_group_keeps_last_iteration() {
+        let caps = captures("cababd".to_string(), "c(ab)+d".to_string()).unwrap().unwrap();
+        assert_eq!(caps[1], Some((3, 5)));
+    }
+
+    #[test]
+    fn test_captures_alternation_records_matched_branch() {
+        let caps = captures("cxyd".to_string(), "c(ab|xy)d".to_string()).unwrap().unwrap();
+        assert_eq!(caps[1], Some((1, 3)));
+    }
+
+    #[test]
+    fn test_captures_no_match_is_none() {
+        assert!(captures("xyz".to_string(), "c(ab)d".to_string()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_counted_repetition_exact() {
+        assert!(is_match("aaa".to_string(), "a{3}".to_string()).unwrap());
+        assert!(!is_match("aa".to_string(), "a{3}".to_string()).unwrap());
+        assert_eq!(find("aaaa".to_string(), "a{3}".to_string()).unwrap(), Some((0, 3)));
+    }
+
+    #[test]
+    fn test_counted_repetition_min_only() {
+        assert!(!is_match("aa".to_string(), "a{3,}".to_string()).unwrap());
+        assert_eq!(find("aaaaa".to_string(), "a{3,}".to_string()).unwrap(), Some((0, 5)));
+    }
+
+    #[test]
+    fn test_counted_repetition_range() {
+        assert_eq!(find("aaaaa".to_string(), "a{2,4}".to_string()).unwrap(), Some((0, 4)));
+        assert!(!is_match("a".to_string(), "a{2,4}".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_counted_repetition_zero_min_is_optional() {
+        assert!(is_match("".to_string(), "a{0,2}".to_string()).unwrap());
+        assert_eq!(find("aa".to_string(), "a{0,2}".to_string()).unwrap(), Some((0, 2)));
+    }
+
+    #[test]
+    fn test_counted_repetition_on_class() {
+        assert!(is_match("123".to_string(), "[0-9]{3}".to_string()).unwrap());
+        assert!(!is_match("12".to_string(), "[0-9]{3}".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_counted_repetition_on_group() {
+        assert!(is_match("ababab".to_string(), "(ab){3}".to_string()).unwrap());
+        assert!(!is_match("abab".to_string(), "(ab){3}".to_string()).unwrap());
+        assert_eq!(find("ababababab".to_string(), "(ab){2,4}".to_string()).unwrap(), Some((0, 8)));
+    }
+
+    #[test]
+    fn test_counted_repetition_followed_by_atom() {
+        assert!(is_match("aab".to_string(), "a{2}b".to_string()).unwrap());
+        assert!(!is_match("ab".to_string(), "a{2}b".to_string()).unwrap());
+        assert!(!is_match("aa".to_string(), "a{2}b".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_counted_repetition_errors() {
+        assert!(is_match("_".to_string(), "a{}".to_string()).is_err());
+        assert!(is_match("_".to_string(), "a{2,1}".to_string()).is_err());
+        assert!(is_match("_".to_string(), "a{abc}".to_string()).is_err());
+        assert!(is_match("_".to_string(), "a{2".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_counted_repetition_zero_exact_is_empty_match() {
+        assert_eq!(find("a".to_string(), "a{0}".to_string()).unwrap(), Some((0, 0)));
+        assert!(is_match("".to_string(), "a{0}".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_counted_repetition_captures_last_iteration() {
+        let caps = captures("abab".to_string(), "(ab){2}".to_string()).unwrap().unwrap();
+        assert_eq!(caps[1], Some((2, 4)));
+
+        let caps = captures("ababab".to_string(), "(ab){2,}".to_string()).unwrap().unwrap();
+        assert_eq!(caps[1], Some((4, 6)));
+
+        let caps = captures("abab".to_string(), "(a(b)){2}".to_string()).unwrap().unwrap();
+        assert_eq!(caps[1], Some((2, 4)));
+        assert_eq!(caps[2], Some((3, 4)));
+    }
+
+    #[test]
+    fn test_escaped_metacharacters_are_literal() {
+        assert!(is_match(".".to_string(), "\\.".to_string()).unwrap());
+        assert!(!is_match("a".to_string(), "\\.".to_string()).unwrap());
+        assert!(is_match("a.b".to_string(), "a\\.b".to_string()).unwrap());
+        assert!(is_match("(".to_string(), "\\(".to_string()).unwrap());
+        assert!(is_match("|".to_string(), "\\|".to_string()).unwrap());
+        assert!(is_match("*".to_string(), "\\*".to_string()).unwrap());
+        assert!(is_match("\\".to_string(), "\\\\".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_digit_class_and_negation() {
+        assert!(is_match("5".to_string(), "\\d".to_string()).unwrap());
+        assert!(!is_match("a".to_string(), "\\d".to_string()).unwrap());
+        assert!(is_match("a".to_string(), "\\D".to_string()).unwrap());
+        assert!(!is_match("5".to_string(), "\\D".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_word_class_and_negation() {
+        assert!(is_match("q".to_string(), "\\w".to_string()).unwrap());
+        assert!(is_match("_".to_string(), "\\w".to_string()).unwrap());
+        assert!(is_match("9".to_string(), "\\w".to_string()).unwrap());
+        assert!(!is_match(" ".to_string(), "\\w".to_string()).unwrap());
+        assert!(is_match(" ".to_string(), "\\W".to_string()).unwrap());
+        assert!(!is_match("q".to_string(), "\\W".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_space_class_and_negation() {
+        assert!(is_match(" ".to_string(), "\\s".to_string()).unwrap());
+        assert!(is_match("\t".to_string(), "\\s".to_string()).unwrap());
+        assert!(!is_match("a".to_string(), "\\s".to_string()).unwrap());
+        assert!(is_match("a".to_string(), "\\S".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_predefined_class_with_quantifier() {
+        assert_eq!(find("price: 12.50".to_string(), "\\d+\\.\\d+".to_string()).unwrap(), Some((7, 12)));
+    }
+
+    #[test]
+    fn test_trailing_backslash_is_error() {
+        assert!(is_match("_".to_string(), "a\\".to_string()).is_err());
+    }
+
     #[test]
     fn test_is_match() {
         assert!(is_match("aaaaxd".to_string(), "a+b?xc*d".to_string()).unwrap());